@@ -24,8 +24,10 @@
 
 use rstd::vec::Vec;
 use rstd::vec;
+use codec::{Decode, Encode, Input, Output, Error};
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Encode, Decode)]
+#[cfg_attr(feature = "test-helpers", derive(arbitrary::Arbitrary))]
 /// State of a transactional layer.
 pub enum TransactionState {
 	/// Data is under change and can still be dropped.
@@ -35,6 +37,12 @@ pub enum TransactionState {
 	/// Data pointing to this indexed historic state should
 	/// not be returned and can be removed.
 	Dropped,
+	/// Data pointing to this indexed historic state belongs to a branch that
+	/// is not currently active (undo-tree navigation). It is invisible to
+	/// lookups like `Dropped`, but unlike `Dropped` it must **not** be pruned:
+	/// the value has to survive so the branch can be revisited with
+	/// [`States::switch_branch`].
+	Detached,
 }
 
 
@@ -63,6 +71,24 @@ impl<V> HistoriedValue<V> {
 	}
 }
 
+// `index` is a `usize` which is intentionally not `Encode`/`Decode` (its width
+// is platform dependent), so the pairing is serialized with the index widened
+// to a `u64`.
+impl<V: Encode> Encode for HistoriedValue<V> {
+	fn encode_to<T: Output>(&self, dest: &mut T) {
+		self.value.encode_to(dest);
+		(self.index as u64).encode_to(dest);
+	}
+}
+
+impl<V: Decode> Decode for HistoriedValue<V> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		let value = V::decode(input)?;
+		let index = u64::decode(input)? as usize;
+		Ok(HistoriedValue { value, index })
+	}
+}
+
 /// Array like buffer for in memory storage.
 /// By in memory we expect that this will
 /// not required persistence and is not serialized.
@@ -73,28 +99,96 @@ type MemoryOnly<V> = smallvec::SmallVec<[HistoriedValue<V>; ALLOCATED_HISTORY]>;
 /// It means that using transaction in a module got a direct allocation cost.
 const ALLOCATED_HISTORY: usize = 2;
 
+/// Low level buffer primitives the `History` algorithms rely on, factored out
+/// so the succession logic is written once and reused across backing stores.
+/// Two implementations exist: the default in-memory [`MemoryOnly`] `SmallVec`
+/// and the arena-backed [`ArenaVec`].
+pub trait HistoryBuffer<V> {
+	/// Current number of stored values.
+	fn len(&self) -> usize;
+	/// Value/index pairing stored at `index`.
+	fn get_state(&self, index: usize) -> HistoriedValue<&V>;
+	/// Mutable access to the value stored at `index`.
+	fn mut_ref(&mut self, index: usize) -> &mut V;
+	/// Append a value without checking for an existing one at the same index.
+	fn push_unchecked(&mut self, value: HistoriedValue<V>);
+	/// Remove and return the top value.
+	fn pop(&mut self) -> Option<HistoriedValue<V>>;
+	/// Drop every value at or above `index`.
+	fn truncate(&mut self, index: usize);
+	/// Drop the `index` bottom values, shifting the rest down.
+	fn truncate_until(&mut self, index: usize);
+}
+
 /// History of value that are related to a state history (eg field `history` of
 /// an `OverlayedChangeSet`).
 ///
-/// Values are always paired with a state history index.
+/// Values are always paired with a state history index. The buffer `B` holding
+/// those pairs is abstracted behind [`HistoryBuffer`]: it defaults to the
+/// in-memory [`MemoryOnly`] `SmallVec`, but an arena-backed [`ArenaVec`] can be
+/// substituted for bulk allocation (see [`ArenaHistory`]).
 #[derive(Debug, Clone)]
 #[cfg_attr(any(test, feature = "test-helpers"), derive(PartialEq))]
-pub struct History<V>(MemoryOnly<V>);
+pub struct History<V, B = MemoryOnly<V>>(B, rstd::marker::PhantomData<V>);
 
 impl<V> Default for History<V> {
 	fn default() -> Self {
-		History(Default::default())
+		History::from_buffer(Default::default())
 	}
 }
 
 // Following implementation are here to isolate
 // buffer specific functions.
-impl<V> History<V> {
+impl<V, B: HistoryBuffer<V>> History<V, B> {
+
+	/// Wrap an existing buffer in a `History`.
+	pub fn from_buffer(buffer: B) -> Self {
+		History(buffer, rstd::marker::PhantomData)
+	}
 
 	fn get_state(&self, index: usize) -> HistoriedValue<&V> {
-		self.0[index].as_ref()
+		self.0.get_state(index)
+	}
+
+	/// Current number of inner states.
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	#[cfg(feature = "test-helpers")]
+	/// State indexes currently referenced by the buffer, bottom to top.
+	pub fn state_indexes(&self) -> Vec<usize> {
+		(0..self.len()).map(|i| self.get_state(i).index).collect()
+	}
+
+	fn truncate(&mut self, index: usize) {
+		self.0.truncate(index)
+	}
+
+	fn truncate_until(&mut self, index: usize) {
+		self.0.truncate_until(index)
 	}
 
+	fn pop(&mut self) -> Option<HistoriedValue<V>> {
+		self.0.pop()
+	}
+
+	/// Append without checking if a value already exist.
+	/// If a value already exists, the history will be broken.
+	/// This method shall only be call after a `get_mut` where
+	/// the returned index indicate that a `set` will result
+	/// in appending a value.
+	pub fn push_unchecked(&mut self, value: HistoriedValue<V>) {
+		self.0.push_unchecked(value)
+	}
+
+	fn mut_ref(&mut self, index: usize) -> &mut V {
+		self.0.mut_ref(index)
+	}
+
+}
+
+impl<V> History<V> {
 	#[cfg(any(test, feature = "test-helpers"))]
 	/// Create an history from an existing history.
 	pub fn from_iter(input: impl IntoIterator<Item = HistoriedValue<V>>) -> Self {
@@ -104,49 +198,106 @@ impl<V> History<V> {
 		}
 		history
 	}
+}
+
+impl<V> HistoryBuffer<V> for MemoryOnly<V> {
+	fn len(&self) -> usize {
+		smallvec::SmallVec::len(self)
+	}
 
-	/// Current number of inner states.
-	pub fn len(&self) -> usize {
-		self.0.len()
+	fn get_state(&self, index: usize) -> HistoriedValue<&V> {
+		self[index].as_ref()
+	}
+
+	fn mut_ref(&mut self, index: usize) -> &mut V {
+		&mut self[index].value
+	}
+
+	fn push_unchecked(&mut self, value: HistoriedValue<V>) {
+		self.push(value)
+	}
+
+	fn pop(&mut self) -> Option<HistoriedValue<V>> {
+		smallvec::SmallVec::pop(self)
 	}
 
 	fn truncate(&mut self, index: usize) {
-		self.0.truncate(index)
+		smallvec::SmallVec::truncate(self, index)
 	}
 
 	fn truncate_until(&mut self, index: usize) {
 		if index > 0 {
-			if self.0.spilled() {
-				let owned = rstd::mem::replace(&mut self.0, Default::default());
-				self.0 = smallvec::SmallVec::from_vec(owned.into_vec().split_off(index));
+			if self.spilled() {
+				let owned = rstd::mem::replace(self, Default::default());
+				*self = smallvec::SmallVec::from_vec(owned.into_vec().split_off(index));
 			} else {
 				for i in (0..index).rev() {
-					self.0.remove(i);
+					self.remove(i);
 				}
 			}
 		}
 	}
+}
 
-	fn pop(&mut self) -> Option<HistoriedValue<V>> {
-		self.0.pop()
+// The in memory buffer is a `SmallVec`, which has no codec implementation; it
+// is serialized as the plain sequence of its `(value, index)` pairs so that
+// the committed index carried by each `HistoriedValue` is preserved. Only the
+// default in-memory backing is serialized; arena-backed histories borrow their
+// storage and are not persisted.
+impl<V: Encode> Encode for History<V> {
+	fn encode_to<T: Output>(&self, dest: &mut T) {
+		self.0.as_slice().encode_to(dest)
 	}
+}
 
-	/// Append without checking if a value already exist.
-	/// If a value already exists, the history will be broken.
-	/// This method shall only be call after a `get_mut` where
-	/// the returned index indicate that a `set` will result
-	/// in appending a value.
-	pub fn push_unchecked(&mut self, value: HistoriedValue<V>) {
-		self.0.push(value)
+impl<V: Decode> Decode for History<V> {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		let values = Vec::<HistoriedValue<V>>::decode(input)?;
+		Ok(History::from_buffer(smallvec::SmallVec::from_vec(values)))
 	}
+}
 
-	fn mut_ref(&mut self, index: usize) -> &mut V {
-		&mut self.0[index].value
+impl<V: Encode + Decode> History<V> {
+	/// Serialize the whole history (including the per-value state indexes) to
+	/// its SCALE encoding so an in-flight overlay can be snapshotted.
+	pub fn snapshot(&self) -> Vec<u8> {
+		self.encode()
 	}
 
+	/// Rebuild a history from a snapshot produced by [`History::snapshot`].
+	pub fn restore(bytes: &[u8]) -> Self {
+		Self::decode(&mut &bytes[..]).expect("valid History snapshot")
+	}
 }
 
 
+/// Queue of deferred side effects attached to a layer index, run once that
+/// layer is committed and dropped if it is discarded (the transaction
+/// "on_commit mechanism"). It is a newtype so that `States` can keep deriving
+/// `Debug`/`Clone`/`PartialEq` even though a boxed `FnOnce` is none of those:
+/// cloning yields an empty queue and all queues compare equal.
+#[derive(Default)]
+struct OnCommit(Vec<(usize, Box<dyn FnOnce()>)>);
+
+impl Clone for OnCommit {
+	fn clone(&self) -> Self {
+		OnCommit(Vec::new())
+	}
+}
+
+impl core::fmt::Debug for OnCommit {
+	fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+		f.debug_struct("OnCommit").field("len", &self.0.len()).finish()
+	}
+}
+
+#[cfg(any(test, feature = "test-helpers"))]
+impl PartialEq for OnCommit {
+	fn eq(&self, _other: &Self) -> bool {
+		true
+	}
+}
+
 /// States is both an indexed state to query values with history
 /// and a committed index that indicates a point in time where
 /// we cannot drop transaction layer.
@@ -154,36 +305,100 @@ impl<V> History<V> {
 /// committed index and all layer can be dropped.
 /// There is a implicit pending state which is equal to the length
 /// of this history.
+///
+/// By default the succession of layers is strictly linear: every layer is the
+/// child of the one before it. An optional branching mode keeps the full
+/// undo-tree alive, recording a `parent` and `children` for every layer and a
+/// `cursor` pointing at the active leaf. In that mode discarding or committing
+/// a transaction only moves the cursor instead of physically dropping the
+/// layers, so a discarded branch can still be revisited with
+/// [`States::switch_branch`]. Lookups only see the layers on the active
+/// branch; this visibility is exposed to `History` by masking the off-branch
+/// layers as `Detached` in the slice returned by `as_ref`. `Detached` differs
+/// from `Dropped` in that its values must survive pruning so the branch stays
+/// revisitable.
 #[derive(Debug, Clone)]
 #[cfg_attr(any(test, feature = "test-helpers"), derive(PartialEq))]
-pub struct States(Vec<TransactionState>, usize);
+pub struct States {
+	/// Transaction state of every layer, indexed by layer.
+	history: Vec<TransactionState>,
+	/// Committed layer index (see type level documentation).
+	committed: usize,
+	/// Parent layer of every layer; the root layer is its own parent.
+	/// Only maintained in branching mode.
+	parents: Vec<usize>,
+	/// Child layers of every layer. Only maintained in branching mode.
+	children: Vec<smallvec::SmallVec<[usize; 1]>>,
+	/// Active leaf layer that pending writes apply to.
+	cursor: usize,
+	/// Layer standing for the committed boundary in branching mode, or `None`
+	/// when nothing is committed yet. Tracked explicitly (rather than derived
+	/// from `committed`) so layer `0` being committed is not conflated with
+	/// nothing being committed.
+	committed_leaf: Option<usize>,
+	/// Masked view of `history` where every layer that is not an ancestor of
+	/// `cursor` is reported as `Detached`. Only populated in branching mode; in
+	/// the linear default `history` is handed out directly.
+	effective: Vec<TransactionState>,
+	/// Whether undo-tree navigation is enabled. Set once at construction.
+	branching: bool,
+	/// Callbacks registered against a layer index, run when that layer is
+	/// committed and dropped when it is discarded.
+	on_commit: OnCommit,
+}
 
 impl Default for States {
 	fn default() -> Self {
-		States(vec![TransactionState::Pending], 0)
+		States {
+			history: vec![TransactionState::Pending],
+			committed: 0,
+			parents: vec![0],
+			children: vec![smallvec::SmallVec::new()],
+			cursor: 0,
+			committed_leaf: None,
+			effective: Vec::new(),
+			branching: false,
+			on_commit: OnCommit::default(),
+		}
 	}
 }
 
 impl States {
+	/// Create an empty state succession with undo-tree navigation enabled.
+	/// Discarded and committed transaction branches are kept alive and can be
+	/// reactivated with [`States::switch_branch`].
+	pub fn branching() -> Self {
+		let mut states = States::default();
+		states.branching = true;
+		states.recompute_effective();
+		states
+	}
+
 	/// Get reference of state, that is enough
 	/// information to query historied
 	/// data.
 	pub fn as_ref(&self) -> &[TransactionState] {
-		self.0.as_ref()
+		if self.branching {
+			self.effective.as_ref()
+		} else {
+			self.history.as_ref()
+		}
 	}
 
 	/// Get reference of state, that is enough
 	/// information to update historied
-	/// data.
-	pub fn as_ref_mut(&self) -> (&[TransactionState], usize) {
-		(self.0.as_ref(), self.1)
+	/// data. The trailing flag reports whether undo-tree navigation is enabled,
+	/// so the mutating buffer operations know they must not prune off-branch
+	/// layers.
+	pub fn as_ref_mut(&self) -> (&[TransactionState], usize, bool) {
+		(self.as_ref(), self.committed, self.branching)
 	}
 
 	/// Get index of committed layer, this is
 	/// additional information needed to manage
 	/// commit and garbage collect.
 	pub fn committed(&self) -> usize {
-		self.1
+		self.committed
 	}
 
 	/// Allow to rollback to a previous committed
@@ -191,75 +406,393 @@ impl States {
 	/// This can only work if there was no eager
 	/// garbage collection.
 	pub fn unchecked_rollback_committed(&mut self, old_committed: usize) {
-		self.1 = old_committed;
+		self.committed = old_committed;
 		self.discard_prospective();
 	}
 
 	/// Build any state for testing only.
 	#[cfg(any(test, feature = "test-helpers"))]
 	pub fn test_vector(test_states: Vec<TransactionState>, committed: usize) -> Self {
-		States(test_states, committed)
+		let len = test_states.len();
+		States {
+			history: test_states,
+			committed,
+			parents: (0..len).map(|i| i.saturating_sub(1)).collect(),
+			children: (0..len).map(|i| {
+				let mut children = smallvec::SmallVec::new();
+				if i + 1 < len {
+					children.push(i + 1);
+				}
+				children
+			}).collect(),
+			cursor: len.saturating_sub(1),
+			committed_leaf: if committed == 0 { None } else { Some(committed - 1) },
+			effective: Vec::new(),
+			branching: false,
+			on_commit: OnCommit::default(),
+		}
+	}
+
+	/// Open a fresh, empty branch root (a self-parented pending layer) and make
+	/// it the cursor. Used when discarding with nothing committed: it detaches
+	/// the whole uncommitted tree so no value stays visible, mirroring the
+	/// linear path which drops `history[0..]`.
+	fn open_fresh_root(&mut self) {
+		let index = self.history.len();
+		self.history.push(TransactionState::Pending);
+		self.parents.push(index);
+		self.children.push(smallvec::SmallVec::new());
+		self.cursor = index;
+	}
+
+	/// Register a callback to run once the current pending layer is committed
+	/// (through `commit_transaction` for a transaction layer or
+	/// `commit_prospective` for a prospective one). It is dropped without
+	/// running if that layer is later discarded.
+	///
+	/// Beware the transaction/prospective mismatch: `commit_transaction` only
+	/// folds a transaction layer into the surrounding *prospective* layer, it
+	/// does not make anything durable. A callback registered inside a
+	/// transaction therefore fires as soon as that transaction is committed,
+	/// even though the underlying data is still prospective and can be thrown
+	/// away by a later [`States::discard_prospective`]. Only register side
+	/// effects here that are safe to run ahead of the final
+	/// [`States::commit_prospective`]; anything that must not outlive a rolled
+	/// back prospective layer should be registered against the prospective
+	/// layer itself.
+	pub fn register_on_commit(&mut self, callback: Box<dyn FnOnce()>) {
+		let layer = self.history.len() - 1;
+		self.on_commit.0.push((layer, callback));
+	}
+
+	/// Run and drain every queued callback whose layer satisfies `run`, keeping
+	/// the others in place. Callbacks fire in registration order.
+	fn run_on_commit(&mut self, run: impl Fn(usize) -> bool) {
+		let queued = rstd::mem::replace(&mut self.on_commit.0, Vec::new());
+		let mut kept = Vec::new();
+		for (layer, callback) in queued {
+			if run(layer) {
+				callback();
+			} else {
+				kept.push((layer, callback));
+			}
+		}
+		self.on_commit.0 = kept;
+	}
+
+	/// Drop, without running, every queued callback whose layer satisfies
+	/// `drop`.
+	fn drop_on_commit(&mut self, drop: impl Fn(usize) -> bool) {
+		self.on_commit.0.retain(|(layer, _)| !drop(*layer));
+	}
+
+	/// Push a new layer as a child of `parent`. In branching mode the new layer
+	/// also becomes the active cursor. Returns the index of the new layer.
+	fn push_layer(&mut self, state: TransactionState, parent: usize) -> usize {
+		let index = self.history.len();
+		self.history.push(state);
+		if self.branching {
+			self.parents.push(parent);
+			self.children.push(smallvec::SmallVec::new());
+			self.children[parent].push(index);
+			self.cursor = index;
+		}
+		index
+	}
+
+	/// Rebuild the `effective` view so that every layer which is not an
+	/// ancestor of `cursor` appears as `Detached`. This realises the undo-tree
+	/// visibility rule: a value is only returned if its layer lies on the
+	/// active branch, while `Detached` (unlike `Dropped`) keeps off-branch
+	/// values safe from pruning.
+	fn recompute_effective(&mut self) {
+		if !self.branching {
+			return;
+		}
+		let mut on_branch = vec![false; self.history.len()];
+		let mut i = self.cursor;
+		loop {
+			on_branch[i] = true;
+			let parent = self.parents[i];
+			if parent == i {
+				break;
+			}
+			i = parent;
+		}
+		self.effective = self.history.iter().enumerate().map(|(i, state)| {
+			if on_branch[i] {
+				state.clone()
+			} else {
+				TransactionState::Detached
+			}
+		}).collect();
 	}
 
 	/// Discard prospective changes to state.
 	/// That is revert all transaction up to the committed index.
 	pub fn discard_prospective(&mut self) {
-		for i in self.1 .. self.0.len() {
-			self.0[i] = TransactionState::Dropped;
+		// Callbacks registered against the discarded prospective layers are
+		// dropped without running.
+		let committed = self.committed;
+		self.drop_on_commit(move |layer| layer >= committed);
+		if self.branching {
+			// Move the cursor back onto the committed leaf (leaving the
+			// prospective layers in place for later navigation) and open a
+			// fresh pending layer. With nothing committed, detach the whole
+			// uncommitted tree so no prospective value survives.
+			match self.committed_leaf {
+				Some(leaf) => {
+					self.cursor = leaf;
+					self.push_layer(TransactionState::Pending, self.cursor);
+				},
+				None => self.open_fresh_root(),
+			}
+			self.recompute_effective();
+			return;
+		}
+		for i in self.committed .. self.history.len() {
+			self.history[i] = TransactionState::Dropped;
 		}
-		self.0.push(TransactionState::Pending);
+		self.history.push(TransactionState::Pending);
 	}
 
 	/// Commit prospective changes to state.
 	pub fn commit_prospective(&mut self) {
-		self.1 = self.0.len();
-		self.0.push(TransactionState::Pending);
+		self.committed = self.history.len();
+		// Every callback registered at or below the new committed index fires.
+		let committed = self.committed;
+		self.run_on_commit(move |layer| layer < committed);
+		if self.branching {
+			// The current leaf becomes the committed boundary.
+			self.committed_leaf = Some(self.cursor);
+			self.push_layer(TransactionState::Pending, self.cursor);
+			self.recompute_effective();
+			return;
+		}
+		self.history.push(TransactionState::Pending);
 	}
 
 	/// Create a new transactional layer.
 	pub fn start_transaction(&mut self) {
-		self.0.push(TransactionState::TxPending);
+		self.push_layer(TransactionState::TxPending, self.cursor);
+		if self.branching {
+			self.recompute_effective();
+		}
 	}
 
 	/// Discard a transactional layer.
 	/// A transaction is always running (history always end with pending).
 	pub fn discard_transaction(&mut self) {
-		let mut i = self.0.len();
-		while i > self.1 {
+		if self.branching {
+			// Walk the active branch up to the nearest transaction start. When a
+			// transaction is open, move the cursor onto its parent, discarding
+			// that transaction while keeping the sibling layers physically
+			// present so `switch_branch` can return to them. When none is open,
+			// discard the whole prospective region down to the committed leaf,
+			// mirroring the linear arm (which marks every prospective pending
+			// layer `Dropped` when it finds no `TxPending`); the off-branch
+			// layers are kept reachable as `Detached` rather than removed.
+			let mut i = self.cursor;
+			let mut found = None;
+			while i > self.committed {
+				if let TransactionState::TxPending = self.history[i] {
+					found = Some(i);
+					break;
+				}
+				let parent = self.parents[i];
+				if parent == i {
+					break;
+				}
+				i = parent;
+			}
+			let dropped_from = match found {
+				Some(tx) => tx,
+				None => self.committed,
+			};
+			self.drop_on_commit(move |layer| layer >= dropped_from);
+			match found {
+				Some(tx) => {
+					self.cursor = self.parents[tx];
+					self.push_layer(TransactionState::Pending, self.cursor);
+				},
+				// No enclosing transaction: fall back to the prospective-discard
+				// behaviour so nothing prospective stays visible.
+				None => match self.committed_leaf {
+					Some(leaf) => {
+						self.cursor = leaf;
+						self.push_layer(TransactionState::Pending, self.cursor);
+					},
+					None => self.open_fresh_root(),
+				},
+			}
+			self.recompute_effective();
+			return;
+		}
+		let mut i = self.history.len();
+		let mut dropped_from = self.committed;
+		while i > self.committed {
 			i -= 1;
-			match self.0[i] {
-				TransactionState::Dropped => (),
+			match self.history[i] {
+				TransactionState::Dropped
+				| TransactionState::Detached => (),
 				TransactionState::Pending => {
-					self.0[i] = TransactionState::Dropped;
+					self.history[i] = TransactionState::Dropped;
 				},
 				TransactionState::TxPending => {
-					self.0[i] = TransactionState::Dropped;
+					self.history[i] = TransactionState::Dropped;
+					dropped_from = i;
 					break;
 				},
 			}
 		}
-		self.0.push(TransactionState::Pending);
+		// Callbacks on the discarded transaction layers are dropped unrun.
+		self.drop_on_commit(move |layer| layer >= dropped_from);
+		self.history.push(TransactionState::Pending);
 	}
 
 	/// Commit a transactional layer.
 	pub fn commit_transaction(&mut self) {
-		let mut i = self.0.len();
-		while i > self.1 {
+		if self.branching {
+			// Absorb the nearest transaction on the active branch into its
+			// parent by clearing its `TxPending` marker; the new pending layer
+			// is opened as a child of the cursor, so the committed work stays
+			// on the active branch.
+			let mut i = self.cursor;
+			let mut committed_layer = None;
+			while i > self.committed {
+				if let TransactionState::TxPending = self.history[i] {
+					self.history[i] = TransactionState::Pending;
+					committed_layer = Some(i);
+					break;
+				}
+				let parent = self.parents[i];
+				if parent == i {
+					break;
+				}
+				i = parent;
+			}
+			if let Some(start) = committed_layer {
+				self.run_on_commit(move |layer| layer >= start);
+			}
+			self.push_layer(TransactionState::Pending, self.cursor);
+			self.recompute_effective();
+			return;
+		}
+		let mut i = self.history.len();
+		let mut committed_layer = None;
+		while i > self.committed {
 			i -= 1;
-			match self.0[i] {
+			match self.history[i] {
 				TransactionState::Pending
-				| TransactionState::Dropped => (),
+				| TransactionState::Dropped
+				| TransactionState::Detached => (),
 				TransactionState::TxPending => {
-					self.0[i] = TransactionState::Pending;
+					self.history[i] = TransactionState::Pending;
+					committed_layer = Some(i);
 					break;
 				},
 			}
 		}
-		self.0.push(TransactionState::Pending);
+		// Run the callbacks registered inside the committed transaction layer.
+		if let Some(start) = committed_layer {
+			self.run_on_commit(move |layer| layer >= start);
+		}
+		self.history.push(TransactionState::Pending);
+	}
+
+	/// Repoint the active cursor onto a different ancestor chain by selecting
+	/// `layer_index` as the new branch point and opening a fresh pending layer
+	/// below it. Only meaningful in branching mode: off-branch layers become
+	/// invisible to `History` lookups while remaining physically stored, so a
+	/// previously discarded branch can be navigated back into.
+	///
+	/// Because off-branch layers must stay reachable, in branching mode both
+	/// [`History::get_mut`] and [`History::get_mut_pruning`] resolve without
+	/// pruning, so no stored value is physically dropped.
+	pub fn switch_branch(&mut self, layer_index: usize) {
+		debug_assert!(self.branching);
+		debug_assert!(layer_index < self.history.len());
+		self.cursor = layer_index;
+		self.push_layer(TransactionState::Pending, self.cursor);
+		self.recompute_effective();
+	}
+
+	/// Enumerate the leaf layers of the undo-tree, that is every layer with no
+	/// children. These are the tips a caller can hand to
+	/// [`States::switch_branch`] to navigate back into a previously discarded or
+	/// committed branch; in linear mode the single pending tip is returned.
+	pub fn branches(&self) -> Vec<usize> {
+		if self.branching {
+			self.children.iter().enumerate()
+				.filter(|(_, c)| c.is_empty())
+				.map(|(i, _)| i)
+				.collect()
+		} else {
+			vec![self.history.len() - 1]
+		}
+	}
+
+	/// Serialize the state succession to its SCALE encoding, so it can be
+	/// persisted alongside the matching [`History`] snapshots and restored on
+	/// another worker.
+	pub fn snapshot(&self) -> Vec<u8> {
+		self.encode()
+	}
+
+	/// Rebuild a state succession from a snapshot produced by
+	/// [`States::snapshot`].
+	pub fn restore(bytes: &[u8]) -> Self {
+		Self::decode(&mut &bytes[..]).expect("valid States snapshot")
 	}
 
 }
 
+// `States` owns `usize` indexes and `SmallVec` child lists that have no direct
+// codec implementation, so each field is serialized explicitly with the
+// indexes widened to `u64`. The `effective` view is derived from the other
+// fields and rebuilt on decode rather than stored.
+impl Encode for States {
+	fn encode_to<T: Output>(&self, dest: &mut T) {
+		self.history.encode_to(dest);
+		(self.committed as u64).encode_to(dest);
+		self.committed_leaf.map(|l| l as u64).encode_to(dest);
+		self.parents.iter().map(|p| *p as u64).collect::<Vec<u64>>().encode_to(dest);
+		self.children.iter()
+			.map(|c| c.iter().map(|i| *i as u64).collect::<Vec<u64>>())
+			.collect::<Vec<Vec<u64>>>()
+			.encode_to(dest);
+		(self.cursor as u64).encode_to(dest);
+		self.branching.encode_to(dest);
+	}
+}
+
+impl Decode for States {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, Error> {
+		let history = Vec::<TransactionState>::decode(input)?;
+		let committed = u64::decode(input)? as usize;
+		let committed_leaf = Option::<u64>::decode(input)?.map(|l| l as usize);
+		let parents = Vec::<u64>::decode(input)?.into_iter().map(|p| p as usize).collect();
+		let children = Vec::<Vec<u64>>::decode(input)?.into_iter()
+			.map(|c| c.into_iter().map(|i| i as usize).collect())
+			.collect();
+		let cursor = u64::decode(input)? as usize;
+		let branching = bool::decode(input)?;
+		let mut states = States {
+			history,
+			committed,
+			committed_leaf,
+			parents,
+			children,
+			cursor,
+			effective: Vec::new(),
+			branching,
+			on_commit: OnCommit::default(),
+		};
+		states.recompute_effective();
+		Ok(states)
+	}
+}
+
 /// Get previous index of pending state.
 /// Used to say if it is possible to drop a committed transaction
 /// state value.
@@ -284,11 +817,11 @@ pub fn find_previous_tx_start(states: (&[TransactionState], usize), from: usize)
 
 
 
-impl<V> History<V> {
+impl<V, B: HistoryBuffer<V>> History<V, B> {
 	/// Set a value, it uses a state history as parameter.
 	/// This method uses `get_mut` and do remove pending
 	/// dropped value.
-	pub fn set(&mut self, states: (&[TransactionState], usize), value: V) {
+	pub fn set(&mut self, states: (&[TransactionState], usize, bool), value: V) {
 		if let Some(v) = self.get_mut(states) {
 			if v.index == states.0.len() - 1 {
 				*v.value = value;
@@ -301,6 +834,35 @@ impl<V> History<V> {
 		});
 	}
 
+	/// Set a value only when it differs from the one currently visible at the
+	/// pending state, returning `true` when a write happened and `false` when
+	/// it was a no-op.
+	///
+	/// This resolves the visible value through the same `get_mut` traversal as
+	/// [`History::set`] and, when it already equals `value`, skips appending (or
+	/// overwriting) an identical historic entry so overlay writers avoid growing
+	/// history on hot paths. Note that `get_mut` is not a pure peek: it prunes
+	/// dropped layers and may fold a transaction switch in place, so the buffer
+	/// can still be mutated even when this returns `false`.
+	pub fn set_if_changed(&mut self, states: (&[TransactionState], usize, bool), value: V) -> bool
+		where V: PartialEq,
+	{
+		if let Some(v) = self.get_mut(states) {
+			if *v.value == value {
+				return false;
+			}
+			if v.index == states.0.len() - 1 {
+				*v.value = value;
+				return true;
+			}
+		}
+		self.push_unchecked(HistoriedValue {
+			value,
+			index: states.0.len() - 1,
+		});
+		true
+	}
+
 	/// Access to latest pending value (non dropped state).
 	/// When possible please prefer `get_mut` as it can free
 	/// some memory.
@@ -315,7 +877,8 @@ impl<V> History<V> {
 			index -= 1;
 			let HistoriedValue { value, index: state_index } = self.get_state(index);
 			match states[state_index] {
-				TransactionState::Dropped => (),
+				TransactionState::Dropped
+				| TransactionState::Detached => (),
 				TransactionState::Pending
 				| TransactionState::TxPending =>
 					return Some(value),
@@ -335,7 +898,8 @@ impl<V> History<V> {
 			index -= 1;
 			let state_index = self.get_state(index).index;
 			match states[state_index] {
-				TransactionState::Dropped => (),
+				TransactionState::Dropped
+				| TransactionState::Detached => (),
 				TransactionState::Pending
 				| TransactionState::TxPending => {
 					self.truncate(index + 1);
@@ -358,7 +922,8 @@ impl<V> History<V> {
 			index -= 1;
 			let HistoriedValue { value, index: state_index } = self.get_state(index);
 			match states[state_index] {
-				TransactionState::Dropped => (),
+				TransactionState::Dropped
+				| TransactionState::Detached => (),
 				TransactionState::Pending
 				| TransactionState::TxPending =>
 					return Some(value),
@@ -379,7 +944,8 @@ impl<V> History<V> {
 			let HistoriedValue { value, index: state_index } = self.get_state(index);
 			if state_index < committed {
 				match states[state_index] {
-					TransactionState::Dropped => (),
+					TransactionState::Dropped
+					| TransactionState::Detached => (),
 					TransactionState::Pending
 					| TransactionState::TxPending =>
 						return Some(value),
@@ -404,7 +970,8 @@ impl<V> History<V> {
 			let state_index = self.get_state(index).index;
 			if state_index < committed {
 				match states[state_index] {
-					TransactionState::Dropped => (),
+					TransactionState::Dropped
+					| TransactionState::Detached => (),
 					TransactionState::Pending
 					| TransactionState::TxPending => {
 						self.truncate(index + 1);
@@ -416,17 +983,44 @@ impl<V> History<V> {
 		None
 	}
 
+	/// Branch-aware resolution used when the state view carries `Detached`
+	/// layers (undo-tree mode): return the most recent on-branch value without
+	/// pruning anything, so off-branch entries stay available for
+	/// [`States::switch_branch`].
+	fn get_mut_no_prune(&mut self, states: &[TransactionState]) -> Option<HistoriedValue<&mut V>> {
+		let mut index = self.len();
+		while index > 0 {
+			index -= 1;
+			let state_index = self.get_state(index).index;
+			match states[state_index] {
+				TransactionState::Pending
+				| TransactionState::TxPending =>
+					return Some((self.mut_ref(index), state_index).into()),
+				TransactionState::Dropped
+				| TransactionState::Detached => (),
+			}
+		}
+		None
+	}
+
 	/// Access to latest pending value (non dropped state).
 	///
 	/// This method removes latest dropped values up to the latest valid value.
 	pub fn get_mut(
 		&mut self,
-		states: (&[TransactionState], usize),
+		states: (&[TransactionState], usize, bool),
 	) -> Option<HistoriedValue<&mut V>> {
 		let mut index = self.len();
 		if index == 0 {
 			return None;
 		}
+		// In branching mode any layer may still be a `switch_branch` target,
+		// even while the tree is a single chain with no `Detached` layer yet, so
+		// resolve without pruning. The flag keeps the default linear path free of
+		// any per-write slice scan.
+		if states.2 {
+			return self.get_mut_no_prune(states.0);
+		}
 		// internal method: should be use properly
 		// (history of the right overlay change set
 		// is size aligned).
@@ -454,13 +1048,14 @@ impl<V> History<V> {
 					} else {
 						if result.is_none() {
 							result = Some((index, state_index));
-							previous_transaction = find_previous_tx_start(states, state_index);
+							previous_transaction = find_previous_tx_start((states.0, states.1), state_index);
 						} else {
 							break;
 						}
 					}
 				},
-				TransactionState::Dropped => (),
+				TransactionState::Dropped
+				| TransactionState::Detached => (),
 			}
 		}
 		if let Some((index, state_index)) = result {
@@ -478,7 +1073,7 @@ impl<V> History<V> {
 				Some((self.mut_ref(index), state_index).into())
 			}
 		} else {
-			self.0.clear();
+			self.truncate(0);
 			None
 		}
 	}
@@ -486,13 +1081,20 @@ impl<V> History<V> {
 
 	pub fn get_mut_pruning(
 		&mut self,
-		states: (&[TransactionState], usize),
+		states: (&[TransactionState], usize, bool),
 		prune_to_commit: bool,
 	) -> Option<HistoriedValue<&mut V>>  {
 		let mut index = self.len();
 		if index == 0 {
 			return None;
 		}
+		// Pruning is branch-aware: in branching mode every layer is still
+		// reachable through `switch_branch`, so nothing may be dropped and we
+		// resolve without pruning regardless of whether a `Detached` layer
+		// already exists.
+		if states.2 {
+			return self.get_mut_no_prune(states.0);
+		}
 		let mut prune_index = 0;
 		// internal method: should be use properly
 		// (history of the right overlay change set
@@ -535,7 +1137,7 @@ impl<V> History<V> {
 					} else {
 						if result.is_none() {
 							result = Some((index, state_index));
-							previous_transaction = find_previous_tx_start(states, state_index);
+							previous_transaction = find_previous_tx_start((states.0, states.1), state_index);
 						} else {
 							if prune_to_commit {
 								if state_index < states.1 {
@@ -547,7 +1149,8 @@ impl<V> History<V> {
 						}
 					}
 				},
-				TransactionState::Dropped => (),
+				TransactionState::Dropped
+				| TransactionState::Detached => (),
 			}
 		}
 		let deleted = if prune_to_commit && prune_index > 0 && result.is_some() {
@@ -571,8 +1174,787 @@ impl<V> History<V> {
 				Some((self.mut_ref(index - deleted), state_index).into())
 			}
 		} else {
-			self.0.clear();
+			self.truncate(0);
 			None
 		}
 	}
 }
+
+
+/// Default number of slots in a freshly allocated arena chunk.
+const ARENA_CHUNK_SIZE: usize = 64;
+
+/// Initial segment capacity handed to a buffer on its first push.
+const ARENA_SEGMENT_INIT: usize = ALLOCATED_HISTORY * 2;
+
+/// Bump/arena backed storage shared by many per-key history buffers.
+///
+/// The default [`History`] backing is a `SmallVec` that spills to its own heap
+/// allocation once it holds more than [`ALLOCATED_HISTORY`] values; touching
+/// thousands of keys inside nested transactions therefore makes thousands of
+/// tiny allocations. With an arena a single overlay owns one `HistoryArena` and
+/// every spilled buffer draws its segment from the shared chunks, so growth
+/// appends into the current chunk and the whole arena is freed in one shot when
+/// it is dropped. The trade-off is that individual buffers can no longer be
+/// freed on their own; it is an opt-in alternate to the `SmallVec` path kept as
+/// the default.
+///
+/// The arena owns only raw memory: each [`ArenaVec`] handle is responsible for
+/// dropping the values it still holds in its own `Drop`, which is why the arena
+/// itself needs no custom `Drop`.
+pub struct HistoryArena<V> {
+	chunks: core::cell::RefCell<Vec<ArenaChunk<V>>>,
+}
+
+/// One contiguous chunk of uninitialized slots plus the count already handed
+/// out from its front.
+struct ArenaChunk<V> {
+	slots: Box<[core::mem::MaybeUninit<HistoriedValue<V>>]>,
+	filled: usize,
+}
+
+impl<V> Default for HistoryArena<V> {
+	fn default() -> Self {
+		HistoryArena { chunks: core::cell::RefCell::new(Vec::new()) }
+	}
+}
+
+impl<V> HistoryArena<V> {
+	/// Create an empty arena.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Create an empty history buffer drawing all of its storage from this
+	/// arena. This is the arena counterpart of `History::default()`: the
+	/// returned [`ArenaHistory`] drives the same `get`/`set` algorithms as the
+	/// default `SmallVec`-backed `History`.
+	pub fn history(&self) -> ArenaHistory<V> {
+		History::from_buffer(ArenaVec::new(self))
+	}
+
+	/// Reserve `cap` contiguous slots, reusing room in the last chunk when it
+	/// fits and allocating a new chunk otherwise. Returns the chunk index, the
+	/// offset of the segment inside it and a pointer to its first slot.
+	fn alloc(&self, cap: usize) -> (usize, usize, *mut HistoriedValue<V>) {
+		let mut chunks = self.chunks.borrow_mut();
+		if let Some(chunk) = chunks.last_mut() {
+			if chunk.slots.len() - chunk.filled >= cap {
+				let offset = chunk.filled;
+				chunk.filled += cap;
+				let ptr = unsafe {
+					chunk.slots.as_mut_ptr().add(offset) as *mut HistoriedValue<V>
+				};
+				return (chunks.len() - 1, offset, ptr);
+			}
+		}
+		let size = core::cmp::max(cap, ARENA_CHUNK_SIZE);
+		let mut slots: Box<[core::mem::MaybeUninit<HistoriedValue<V>>]> =
+			(0..size).map(|_| core::mem::MaybeUninit::uninit()).collect();
+		let ptr = slots.as_mut_ptr() as *mut HistoriedValue<V>;
+		chunks.push(ArenaChunk { slots, filled: cap });
+		(chunks.len() - 1, 0, ptr)
+	}
+
+	/// Try to extend the segment ending at `end` in place by up to `want` extra
+	/// slots; this only succeeds when that segment ends at the filled frontier
+	/// of the last chunk. Returns the number of slots gained.
+	fn grow_in_place(&self, chunk: usize, end: usize, want: usize) -> usize {
+		let mut chunks = self.chunks.borrow_mut();
+		if chunk + 1 != chunks.len() {
+			return 0;
+		}
+		let chunk = &mut chunks[chunk];
+		if chunk.filled != end {
+			return 0;
+		}
+		let extra = core::cmp::min(want, chunk.slots.len() - chunk.filled);
+		chunk.filled += extra;
+		extra
+	}
+}
+
+/// Growable history buffer whose storage lives in a [`HistoryArena`]. It has
+/// no inline storage: every value, from the first one pushed, is held in an
+/// arena segment.
+///
+/// It mirrors the buffer primitives the [`History`] algorithms rely on
+/// (`len`, `push_unchecked`, `pop`, `truncate`, `get_state`, `mut_ref`), so an
+/// arena-backed `OverlayedChangeSet` can use it in place of the default
+/// `SmallVec`. Growth copies the live values to a fresh, larger segment at the
+/// arena frontier (or extends the current one in place when it is the
+/// frontier), leaving the old slots as dead space reclaimed with the arena.
+pub struct ArenaVec<'a, V> {
+	arena: &'a HistoryArena<V>,
+	ptr: *mut HistoriedValue<V>,
+	chunk: usize,
+	offset: usize,
+	len: usize,
+	cap: usize,
+	_marker: core::marker::PhantomData<HistoriedValue<V>>,
+}
+
+/// Arena-backed counterpart of [`History`], kept as an opt-in alternate to the
+/// default `SmallVec` storage.
+pub type ArenaHistory<'a, V> = History<V, ArenaVec<'a, V>>;
+
+impl<'a, V> ArenaVec<'a, V> {
+	fn new(arena: &'a HistoryArena<V>) -> Self {
+		ArenaVec {
+			arena,
+			ptr: core::ptr::NonNull::dangling().as_ptr(),
+			chunk: 0,
+			offset: 0,
+			len: 0,
+			cap: 0,
+			_marker: core::marker::PhantomData,
+		}
+	}
+
+	fn as_slice(&self) -> &[HistoriedValue<V>] {
+		unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
+	}
+
+	fn get_state(&self, index: usize) -> HistoriedValue<&V> {
+		self.as_slice()[index].as_ref()
+	}
+
+	fn mut_ref(&mut self, index: usize) -> &mut V {
+		unsafe { &mut (*self.ptr.add(index)).value }
+	}
+
+	/// Reserve room for one more value, extending at the frontier when possible
+	/// and relocating to a larger segment otherwise.
+	fn reserve_one(&mut self) {
+		if self.len < self.cap {
+			return;
+		}
+		let want = core::cmp::max(self.cap, ARENA_SEGMENT_INIT);
+		if self.cap > 0 {
+			let gained = self.arena.grow_in_place(self.chunk, self.offset + self.cap, want);
+			if gained > 0 {
+				self.cap += gained;
+				return;
+			}
+		}
+		let new_cap = self.cap + want;
+		let (chunk, offset, ptr) = self.arena.alloc(new_cap);
+		unsafe { core::ptr::copy_nonoverlapping(self.ptr, ptr, self.len); }
+		self.ptr = ptr;
+		self.chunk = chunk;
+		self.offset = offset;
+		self.cap = new_cap;
+	}
+
+}
+
+impl<'a, V> HistoryBuffer<V> for ArenaVec<'a, V> {
+	fn len(&self) -> usize {
+		self.len
+	}
+
+	fn get_state(&self, index: usize) -> HistoriedValue<&V> {
+		ArenaVec::get_state(self, index)
+	}
+
+	fn mut_ref(&mut self, index: usize) -> &mut V {
+		ArenaVec::mut_ref(self, index)
+	}
+
+	/// Append without checking for an existing value (see
+	/// [`History::push_unchecked`]).
+	fn push_unchecked(&mut self, value: HistoriedValue<V>) {
+		self.reserve_one();
+		unsafe { core::ptr::write(self.ptr.add(self.len), value); }
+		self.len += 1;
+	}
+
+	fn pop(&mut self) -> Option<HistoriedValue<V>> {
+		if self.len == 0 {
+			None
+		} else {
+			self.len -= 1;
+			Some(unsafe { core::ptr::read(self.ptr.add(self.len)) })
+		}
+	}
+
+	fn truncate(&mut self, index: usize) {
+		while self.len > index {
+			self.len -= 1;
+			unsafe { core::ptr::drop_in_place(self.ptr.add(self.len)); }
+		}
+	}
+
+	fn truncate_until(&mut self, index: usize) {
+		if index == 0 {
+			return;
+		}
+		let index = core::cmp::min(index, self.len);
+		for i in 0..index {
+			unsafe { core::ptr::drop_in_place(self.ptr.add(i)); }
+		}
+		let remaining = self.len - index;
+		// Shift the surviving tail down to the segment front; the ranges can
+		// overlap so `copy` (memmove) is required, not `copy_nonoverlapping`.
+		unsafe { core::ptr::copy(self.ptr.add(index), self.ptr, remaining); }
+		self.len = remaining;
+	}
+}
+
+// A plain `Drop` (no `#[may_dangle]` eyepatch): the eyepatch needs the nightly
+// `dropck_eyepatch` feature enabled at the crate root, which substrate core
+// crates do not rely on. The conservative drop check is fine here — a handle
+// never outlives its arena.
+impl<'a, V> Drop for ArenaVec<'a, V> {
+	fn drop(&mut self) {
+		// Only drop the values still live in this segment; slots left behind by
+		// a relocation were moved out and the backing memory is reclaimed with
+		// the arena.
+		for i in 0..self.len {
+			unsafe { core::ptr::drop_in_place(self.ptr.add(i)); }
+		}
+	}
+}
+
+/// Differential ("model based") fuzzing harness.
+///
+/// This drives a real `States` + `History<u32>` through an `arbitrary`-generated
+/// sequence of [`Op`]s while maintaining a trivially correct reference model,
+/// and asserts after every operation that the real structure's `get`,
+/// `get_committed` and `get_prospective` agree with the model as well as the
+/// structural invariants the production code relies on but never checks at
+/// runtime. It is meant to be driven from a `cargo-fuzz` target or a property
+/// test and is therefore only built under the `test-helpers` feature.
+#[cfg(feature = "test-helpers")]
+pub mod fuzz {
+	use super::*;
+
+	/// One operation applied in lockstep to the real structure and the model.
+	#[derive(Debug, Clone, arbitrary::Arbitrary)]
+	pub enum Op {
+		/// Write a value at the current pending layer.
+		Set(u32),
+		/// Open a new transactional layer.
+		StartTransaction,
+		/// Commit the innermost transactional layer.
+		CommitTransaction,
+		/// Discard the innermost transactional layer.
+		DiscardTransaction,
+		/// Commit every prospective layer.
+		CommitProspective,
+		/// Discard every prospective layer.
+		DiscardProspective,
+		/// Resolve the pending value through `get_mut` (prunes dropped values).
+		GetMut,
+		/// Resolve the pending value through `get_mut_pruning` (garbage collects
+		/// committed layers).
+		GetMutPruning,
+	}
+
+	/// Trivially correct reference model: one frame per state layer carrying the
+	/// layer state and the value last written to it. Every transaction op mutates
+	/// the frames by brute force, exactly mirroring `States`.
+	struct Model {
+		frames: Vec<(TransactionState, Option<u32>)>,
+		committed: usize,
+	}
+
+	impl Model {
+		fn new() -> Self {
+			Model {
+				frames: vec![(TransactionState::Pending, None)],
+				committed: 0,
+			}
+		}
+
+		fn set(&mut self, value: u32) {
+			// `History::set` always writes at the top (pending) layer.
+			if let Some(frame) = self.frames.last_mut() {
+				frame.1 = Some(value);
+			}
+		}
+
+		fn start_transaction(&mut self) {
+			self.frames.push((TransactionState::TxPending, None));
+		}
+
+		fn discard_transaction(&mut self) {
+			let mut i = self.frames.len();
+			while i > self.committed {
+				i -= 1;
+				match self.frames[i].0 {
+					TransactionState::Dropped
+					| TransactionState::Detached => (),
+					TransactionState::Pending => {
+						self.frames[i].0 = TransactionState::Dropped;
+					},
+					TransactionState::TxPending => {
+						self.frames[i].0 = TransactionState::Dropped;
+						break;
+					},
+				}
+			}
+			self.frames.push((TransactionState::Pending, None));
+		}
+
+		fn commit_transaction(&mut self) {
+			let mut i = self.frames.len();
+			while i > self.committed {
+				i -= 1;
+				match self.frames[i].0 {
+					TransactionState::Pending
+					| TransactionState::Dropped
+					| TransactionState::Detached => (),
+					TransactionState::TxPending => {
+						self.frames[i].0 = TransactionState::Pending;
+						break;
+					},
+				}
+			}
+			self.frames.push((TransactionState::Pending, None));
+		}
+
+		fn discard_prospective(&mut self) {
+			for i in self.committed .. self.frames.len() {
+				self.frames[i].0 = TransactionState::Dropped;
+			}
+			self.frames.push((TransactionState::Pending, None));
+		}
+
+		fn commit_prospective(&mut self) {
+			self.committed = self.frames.len();
+			self.frames.push((TransactionState::Pending, None));
+		}
+
+		/// Highest non-dropped frame that carries a value.
+		fn get(&self) -> Option<u32> {
+			self.frames.iter().rev()
+				.find(|(state, value)| *state != TransactionState::Dropped && value.is_some())
+				.and_then(|(_, value)| *value)
+		}
+
+		/// Highest committed non-dropped frame that carries a value.
+		fn get_committed(&self) -> Option<u32> {
+			self.frames.iter().enumerate().rev()
+				.find(|(i, (state, value))|
+					*i < self.committed && *state != TransactionState::Dropped && value.is_some())
+				.and_then(|(_, (_, value))| *value)
+		}
+
+		/// Highest prospective (non-committed) non-dropped frame with a value.
+		fn get_prospective(&self) -> Option<u32> {
+			self.frames.iter().enumerate().rev()
+				.find(|(i, (state, value))|
+					*i >= self.committed && *state != TransactionState::Dropped && value.is_some())
+				.and_then(|(_, (_, value))| *value)
+		}
+	}
+
+	/// Build a sequence of operations from raw fuzzer input and check it. The
+	/// leading bool selects linear vs branching mode so the fuzzer exercises
+	/// both state machines.
+	pub fn fuzz(data: &[u8]) {
+		let mut input = arbitrary::Unstructured::new(data);
+		if let Ok((branching, ops)) = <(bool, Vec<Op>)>::arbitrary(&mut input) {
+			check_ops(branching, &ops);
+		}
+	}
+
+	/// Drive the real structure (and, in linear mode, the reference model)
+	/// through `ops`, asserting they stay in agreement and that the structural
+	/// invariants hold after each step. The reference model only tracks the
+	/// linear semantics, so in branching mode only the branch-invariant checks
+	/// apply: the undo-tree must keep every layer available, so a resolving op
+	/// may never prune an entry.
+	pub fn check_ops(branching: bool, ops: &[Op]) {
+		let mut states = if branching { States::branching() } else { States::default() };
+		let mut history = History::<u32>::default();
+		let mut model = Model::new();
+		for op in ops {
+			match op {
+				Op::Set(value) => {
+					history.set(states.as_ref_mut(), *value);
+					model.set(*value);
+				},
+				Op::StartTransaction => {
+					states.start_transaction();
+					model.start_transaction();
+				},
+				Op::CommitTransaction => {
+					states.commit_transaction();
+					model.commit_transaction();
+				},
+				Op::DiscardTransaction => {
+					states.discard_transaction();
+					model.discard_transaction();
+				},
+				Op::CommitProspective => {
+					states.commit_prospective();
+					model.commit_prospective();
+				},
+				Op::DiscardProspective => {
+					states.discard_prospective();
+					model.discard_prospective();
+				},
+				Op::GetMut => {
+					let before = history.state_indexes();
+					let _ = history.get_mut(states.as_ref_mut());
+					if branching {
+						// Branching resolution must not prune: every stored entry
+						// survives so it stays reachable via `switch_branch`.
+						assert_eq!(history.state_indexes(), before);
+					} else {
+						// Every stored entry must point at a non-dropped state.
+						for index in history.state_indexes() {
+							assert!(states.as_ref()[index] != TransactionState::Dropped);
+						}
+					}
+				},
+				Op::GetMutPruning => {
+					let committed = states.committed();
+					let before = history.state_indexes();
+					let before_committed = history
+						.get_committed(states.as_ref(), committed).cloned();
+					let before_prospective = history
+						.get_prospective(states.as_ref(), committed).cloned();
+					let _ = history.get_mut_pruning(states.as_ref_mut(), true);
+					let after = history.state_indexes();
+					if branching {
+						// Branching mode never prunes.
+						assert_eq!(after, before);
+					} else {
+						// Structural invariant: garbage collection only removes
+						// entries in the committed region; no entry whose state
+						// index is at or above the committed boundary is dropped.
+						let before_prospective_entries =
+							before.iter().filter(|i| **i >= committed).count();
+						let after_prospective_entries =
+							after.iter().filter(|i| **i >= committed).count();
+						assert!(
+							after_prospective_entries >= before_prospective_entries,
+							"get_mut_pruning dropped a prospective entry",
+						);
+					}
+					// Pruning must never change the visible committed or
+					// prospective value.
+					assert_eq!(
+						history.get_prospective(states.as_ref(), committed).cloned(),
+						before_prospective,
+					);
+					assert_eq!(
+						history.get_committed(states.as_ref(), committed).cloned(),
+						before_committed,
+					);
+				},
+			}
+
+			assert!(history.len() <= states.as_ref().len());
+			// The reference model only mirrors the linear state machine.
+			if !branching {
+				assert_eq!(history.get(states.as_ref()).cloned(), model.get());
+				assert_eq!(
+					history.get_committed(states.as_ref(), states.committed()).cloned(),
+					model.get_committed(),
+				);
+				assert_eq!(
+					history.get_prospective(states.as_ref(), states.committed()).cloned(),
+					model.get_prospective(),
+				);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Drive a `History<V>` the way an overlay would: always reading the state
+	// slice and committed index straight from `States`.
+	fn set(states: &States, history: &mut History<u32>, value: u32) {
+		history.set(states.as_ref_mut(), value);
+	}
+
+	fn get(states: &States, history: &History<u32>) -> Option<u32> {
+		history.get(states.as_ref()).cloned()
+	}
+
+	// The differential harness lives behind the `test-helpers` feature; give it
+	// deterministic coverage in both modes when that feature is enabled.
+	#[cfg(feature = "test-helpers")]
+	#[test]
+	fn fuzz_harness_both_modes_smoke() {
+		use super::fuzz::{check_ops, Op};
+		let ops = [
+			Op::Set(1),
+			Op::StartTransaction,
+			Op::Set(2),
+			Op::CommitTransaction,
+			Op::Set(3),
+			Op::GetMut,
+			Op::StartTransaction,
+			Op::Set(4),
+			Op::DiscardTransaction,
+			Op::GetMutPruning,
+			Op::CommitProspective,
+			Op::DiscardProspective,
+		];
+		check_ops(false, &ops);
+		check_ops(true, &ops);
+	}
+
+	#[test]
+	fn branching_discard_keeps_off_branch_value() {
+		// The regression that motivated the `Detached` marker: a value written
+		// inside a discarded transaction must survive so the branch can be
+		// revisited, instead of being pruned by the next `set`.
+		let mut states = States::branching();
+		let mut history = History::default();
+
+		set(&states, &mut history, 0xA); // layer 0
+		states.start_transaction();
+		set(&states, &mut history, 0xB); // layer 1 (transaction)
+		states.discard_transaction();
+		// Back on the root branch, `b` is off-branch and invisible.
+		assert_eq!(get(&states, &history), Some(0xA));
+		set(&states, &mut history, 0xC); // layer 2
+
+		// Navigating back into the discarded branch must still see `b`.
+		states.switch_branch(1);
+		assert_eq!(get(&states, &history), Some(0xB));
+	}
+
+	#[test]
+	fn branching_commit_transaction_keeps_pre_transaction_value() {
+		// Single-chain branching history with no `Detached` layer yet: the
+		// commit_transaction compaction must not fold the committed value into
+		// layer 0, or the pre-transaction value becomes an unreachable
+		// switch_branch target.
+		let mut states = States::branching();
+		let mut history = History::default();
+
+		set(&states, &mut history, 0xA); // layer 0
+		states.start_transaction();
+		set(&states, &mut history, 0xB); // layer 1 (transaction)
+		states.commit_transaction();
+		set(&states, &mut history, 0xC); // layer 2
+
+		// The active branch sees the latest value.
+		assert_eq!(get(&states, &history), Some(0xC));
+		// Switching back to layer 0 must still yield the pre-transaction `A`.
+		states.switch_branch(0);
+		assert_eq!(get(&states, &history), Some(0xA));
+	}
+
+	#[test]
+	fn branching_discard_transaction_without_open_transaction_discards_prospective() {
+		// With no enclosing transaction open, discard_transaction must discard
+		// the prospective region down to the committed leaf, matching the linear
+		// arm rather than leaving the prospective value visible.
+		let mut states = States::branching();
+		let mut history = History::default();
+
+		set(&states, &mut history, 0xA); // layer 0
+		states.commit_prospective();
+		set(&states, &mut history, 0xB); // prospective, no transaction open
+		states.discard_transaction();
+		assert_eq!(get(&states, &history), Some(0xA));
+	}
+
+	#[test]
+	fn branching_discard_prospective_with_nothing_committed_detaches_tree() {
+		// With nothing committed, `discard_prospective` opens a fresh root so no
+		// prospective value stays visible, but the old tree is kept for
+		// navigation rather than conflated with a committed layer 0.
+		let mut states = States::branching();
+		let mut history = History::default();
+
+		set(&states, &mut history, 0xA); // layer 0, never committed
+		states.discard_prospective();
+		assert_eq!(get(&states, &history), None);
+
+		// The detached value is still reachable by switching back to layer 0.
+		states.switch_branch(0);
+		assert_eq!(get(&states, &history), Some(0xA));
+	}
+
+	#[test]
+	fn states_snapshot_round_trips_linear() {
+		let mut states = States::default();
+		let mut history = History::default();
+		set(&states, &mut history, 0xA);
+		states.start_transaction();
+		set(&states, &mut history, 0xB);
+		states.commit_prospective();
+
+		let restored = States::restore(&states.snapshot());
+		assert_eq!(restored, states);
+
+		let restored_history = History::<u32>::restore(&history.snapshot());
+		assert_eq!(restored_history, history);
+	}
+
+	#[test]
+	fn states_snapshot_round_trips_branching() {
+		let mut states = States::branching();
+		let mut history = History::default();
+		set(&states, &mut history, 0xA);
+		states.start_transaction();
+		set(&states, &mut history, 0xB);
+		states.discard_transaction();
+		set(&states, &mut history, 0xC);
+
+		// The undo-tree, including `committed_leaf` and the detached branch,
+		// must come back unchanged.
+		let restored = States::restore(&states.snapshot());
+		assert_eq!(restored, states);
+		assert_eq!(restored.branches(), states.branches());
+	}
+
+	#[test]
+	fn commit_transaction_runs_callbacks_discard_drops_them() {
+		use std::rc::Rc;
+		use std::cell::Cell;
+
+		// Committing the transaction runs its callback.
+		let mut states = States::default();
+		let fired = Rc::new(Cell::new(0u32));
+		states.start_transaction();
+		let f = fired.clone();
+		states.register_on_commit(Box::new(move || f.set(f.get() + 1)));
+		states.commit_transaction();
+		assert_eq!(fired.get(), 1);
+
+		// Discarding the transaction drops its callback unrun.
+		let mut states = States::default();
+		let fired = Rc::new(Cell::new(0u32));
+		states.start_transaction();
+		let f = fired.clone();
+		states.register_on_commit(Box::new(move || f.set(f.get() + 1)));
+		states.discard_transaction();
+		assert_eq!(fired.get(), 0);
+	}
+
+	#[test]
+	fn commit_transaction_callback_fires_before_prospective_rollback() {
+		use std::rc::Rc;
+		use std::cell::Cell;
+
+		// Documents the footgun: a callback committed with the transaction runs
+		// immediately, even though `discard_prospective` later rolls the data
+		// back. The side effect is not undone.
+		let mut states = States::default();
+		let fired = Rc::new(Cell::new(0u32));
+		states.start_transaction();
+		let f = fired.clone();
+		states.register_on_commit(Box::new(move || f.set(f.get() + 1)));
+		states.commit_transaction();
+		assert_eq!(fired.get(), 1);
+		states.discard_prospective();
+		assert_eq!(fired.get(), 1);
+	}
+
+	#[test]
+	fn branching_commit_prospective_records_committed_leaf() {
+		// After committing, discarding a later prospective layer rolls back onto
+		// the committed leaf rather than detaching the committed work.
+		let mut states = States::branching();
+		let mut history = History::default();
+
+		set(&states, &mut history, 0xA); // layer 0
+		states.commit_prospective();
+		set(&states, &mut history, 0xB); // prospective
+		states.discard_prospective();
+		assert_eq!(get(&states, &history), Some(0xA));
+	}
+
+	#[test]
+	fn set_if_changed_covers_noop_overwrite_and_push() {
+		let mut states = States::default();
+		let mut history = History::default();
+
+		// First write on an empty history appends a new layer.
+		assert!(history.set_if_changed(states.as_ref_mut(), 0xA));
+		assert_eq!(history.len(), 1);
+
+		// Writing the same value at the same pending layer is a no-op.
+		assert!(!history.set_if_changed(states.as_ref_mut(), 0xA));
+		assert_eq!(history.len(), 1);
+
+		// A different value overwrites in place at the top layer.
+		assert!(history.set_if_changed(states.as_ref_mut(), 0xB));
+		assert_eq!(history.len(), 1);
+		assert_eq!(get(&states, &history), Some(0xB));
+
+		// A changed value on a fresh layer pushes instead of overwriting.
+		states.start_transaction();
+		assert!(history.set_if_changed(states.as_ref_mut(), 0xC));
+		assert_eq!(history.len(), 2);
+		assert_eq!(get(&states, &history), Some(0xC));
+
+		// Equal to the top-layer value again: still a no-op.
+		assert!(!history.set_if_changed(states.as_ref_mut(), 0xC));
+		assert_eq!(history.len(), 2);
+	}
+
+	// Enough distinct layers to spill out of a single arena chunk and force at
+	// least one relocation (and many in-place grows beforehand).
+	const ARENA_STRESS: usize = ARENA_CHUNK_SIZE * 4;
+
+	#[test]
+	fn arena_history_matches_memory_backing() {
+		// The arena-backed buffer must drive the exact same algorithms as the
+		// default `SmallVec`, across spills, relocations and a pruning pass.
+		let arena = HistoryArena::new();
+		let mut mem: History<u32> = History::default();
+		let mut ar = arena.history();
+		let mut states = States::default();
+
+		for v in 0..ARENA_STRESS as u32 {
+			states.start_transaction();
+			mem.set(states.as_ref_mut(), v);
+			ar.set(states.as_ref_mut(), v);
+		}
+		assert_eq!(ar.len(), mem.len());
+		assert_eq!(ar.get(states.as_ref()).cloned(), mem.get(states.as_ref()).cloned());
+
+		// Exercise `truncate_until` on the arena through a garbage collecting
+		// pruning pass and confirm both backings agree afterwards.
+		states.commit_prospective();
+		let mem_pruned = mem.get_mut_pruning(states.as_ref_mut(), true).map(|v| *v.value);
+		let ar_pruned = ar.get_mut_pruning(states.as_ref_mut(), true).map(|v| *v.value);
+		assert_eq!(ar_pruned, mem_pruned);
+		assert_eq!(ar.get(states.as_ref()).cloned(), mem.get(states.as_ref()).cloned());
+	}
+
+	#[test]
+	fn arena_history_drops_each_value_once() {
+		use std::rc::Rc;
+		use std::cell::Cell;
+
+		struct Dropper(Rc<Cell<usize>>);
+		impl Drop for Dropper {
+			fn drop(&mut self) {
+				self.0.set(self.0.get() + 1);
+			}
+		}
+
+		let count = Rc::new(Cell::new(0usize));
+		let arena = HistoryArena::new();
+		{
+			let mut history = arena.history();
+			// Pushing past a chunk boundary relocates the live values; the
+			// abandoned source slots must not be dropped again.
+			for i in 0..ARENA_STRESS {
+				history.push_unchecked(HistoriedValue { value: Dropper(count.clone()), index: i });
+			}
+			assert_eq!(history.len(), ARENA_STRESS);
+			// Dropping the top values runs their destructors immediately.
+			let _ = history.pop();
+			let _ = history.pop();
+			assert_eq!(count.get(), 2);
+		}
+		// The remaining live values are dropped exactly once with the buffer.
+		assert_eq!(count.get(), ARENA_STRESS);
+	}
+}